@@ -6,28 +6,70 @@ use io::{Result, Read, Write};
 use mem;
 use os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use ops::DerefMut;
+use slice;
 use string::{String, ToString};
 use core_collections::borrow::ToOwned;
+use sync::Mutex;
 use vec::Vec;
 
 use io::Error;
-use syscall::{self, clone, close, dup, execve, pipe2, read, write, waitpid, CLONE_VM, CLONE_VFORK, CLONE_SUPERVISE};
+use syscall::{self, chdir, clone, close, dup, execve, fcntl, getuid, ioctl, kill, open, openpty, pipe2, read, setgid, setgroups, setsid, setuid, write, waitpid, CLONE_VM, CLONE_VFORK, CLONE_SUPERVISE, ESRCH, SIGKILL, TIOCSCTTY, TIOCSWINSZ, WNOHANG};
+use syscall::flag::{F_GETFL, F_SETFL, O_NONBLOCK, EVENT_READ};
+use syscall::data::Event;
 use syscall::Error as SysError;
 
+/// Pids of dropped `Child`s not yet reaped; drained opportunistically to avoid zombies.
+static ORPHANS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+/// Non-blockingly reap every pid in `ORPHANS` that has already exited.
+fn reap_orphans() {
+    let mut orphans = ORPHANS.lock();
+    let mut status: usize = 0;
+    orphans.retain(|&pid| {
+        match waitpid(pid, &mut status, WNOHANG) {
+            Ok(0) => true,
+            _ => false,
+        }
+    });
+}
+
+#[derive(Copy, Clone)]
 pub struct ExitStatus {
     status: usize,
 }
 
 impl ExitStatus {
     pub fn success(&self) -> bool {
-        self.status == 0
+        self.code() == Some(0)
     }
 
+    /// The exit code the process returned, or `None` if it was terminated by a signal.
     pub fn code(&self) -> Option<i32> {
-        Some(self.status as i32)
+        if self.status & 0x7f == 0 {
+            Some(((self.status >> 8) & 0xff) as i32)
+        } else {
+            None
+        }
+    }
+
+    /// The signal that terminated the process, or `None` if it exited normally.
+    pub fn signal(&self) -> Option<i32> {
+        let sig = self.status & 0x7f;
+        if sig != 0 && sig != 0x7f {
+            Some(sig as i32)
+        } else {
+            None
+        }
     }
 }
 
+/// The output of a finished process.
+pub struct Output {
+    pub status: ExitStatus,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
 pub struct ChildStdin {
     fd: usize,
 }
@@ -83,8 +125,47 @@ impl Drop for ChildStderr {
     }
 }
 
+/// The master end of the pseudo-terminal allocated for a child spawned with `Command::pty()`.
+pub struct ChildPty {
+    fd: usize,
+}
+
+impl ChildPty {
+    /// Notify the terminal (and the child's line discipline) of a new window size.
+    pub fn resize(&self, cols: u16, rows: u16) -> Result<()> {
+        ioctl_resize(self.fd, cols, rows)
+    }
+}
+
+impl Read for ChildPty {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        read(self.fd, buf).map_err(|x| Error::from_sys(x))
+    }
+}
+
+impl Write for ChildPty {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        write(self.fd, buf).map_err(|x| Error::from_sys(x))
+    }
+    fn flush(&mut self) -> Result<()> { Ok(()) }
+}
+
+impl AsRawFd for ChildPty {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for ChildPty {
+    fn drop(&mut self) {
+        let _ = close(self.fd);
+    }
+}
+
 pub struct Child {
     pid: usize,
+    exit_status: Option<ExitStatus>,
+    pub pty: Option<ChildPty>,
     pub stdin: Option<ChildStdin>,
     pub stdout: Option<ChildStdout>,
     pub stderr: Option<ChildStderr>,
@@ -96,15 +177,197 @@ impl Child {
     }
 
     pub fn wait(&mut self) -> Result<ExitStatus> {
+        if let Some(status) = self.exit_status {
+            return Ok(status);
+        }
+
+        reap_orphans();
+
         let mut status: usize = 0;
-        waitpid(self.pid, &mut status, 0).map(|_| ExitStatus { status: status }).map_err(|x| Error::from_sys(x))
+        let exit_status = try!(waitpid(self.pid, &mut status, 0).map(|_| ExitStatus { status: status }).map_err(|x| Error::from_sys(x)));
+        self.exit_status = Some(exit_status);
+        Ok(exit_status)
+    }
+
+    /// Check whether the child has exited without blocking; `Ok(None)` if it's still running.
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        if let Some(status) = self.exit_status {
+            return Ok(Some(status));
+        }
+
+        reap_orphans();
+
+        let mut status: usize = 0;
+        match waitpid(self.pid, &mut status, WNOHANG) {
+            Ok(0) => Ok(None),
+            Ok(_) => {
+                let exit_status = ExitStatus { status: status };
+                self.exit_status = Some(exit_status);
+                Ok(Some(exit_status))
+            },
+            Err(err) => Err(Error::from_sys(err)),
+        }
+    }
+
+    /// Force the child to exit immediately by sending it `SIGKILL`.
+    pub fn kill(&mut self) -> Result<()> {
+        self.signal(SIGKILL)
+    }
+
+    /// Send an arbitrary signal to the child. Errors if it has already been reaped, since its
+    /// pid may since have been recycled for an unrelated process.
+    pub fn signal(&mut self, sig: usize) -> Result<()> {
+        if try!(self.try_wait()).is_some() {
+            return Err(Error::from_sys(SysError::new(ESRCH)));
+        }
+        kill(self.pid, sig).map(|_| ()).map_err(|x| Error::from_sys(x))
+    }
+
+    /// Wait for the child to exit, collecting its stdout/stderr without risking the deadlock a
+    /// naive read-to-end-then-read-to-end would hit if both pipes fill up at once.
+    pub fn wait_with_output(mut self) -> Result<Output> {
+        drop(self.stdin.take());
+
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+
+        match (self.stdout.take(), self.stderr.take()) {
+            (None, None) => (),
+            (Some(mut out), None) => { try!(out.read_to_end(&mut stdout)); },
+            (None, Some(mut err)) => { try!(err.read_to_end(&mut stderr)); },
+            (Some(out), Some(err)) => {
+                try!(read2(out.fd, err.fd, &mut stdout, &mut stderr));
+            }
+        }
+
+        let status = try!(self.wait());
+
+        Ok(Output {
+            status: status,
+            stdout: stdout,
+            stderr: stderr,
+        })
+    }
+}
+
+impl Drop for Child {
+    fn drop(&mut self) {
+        if self.exit_status.is_none() {
+            let mut status: usize = 0;
+            if let Ok(0) = waitpid(self.pid, &mut status, WNOHANG) {
+                ORPHANS.lock().push(self.pid);
+            }
+        }
+    }
+}
+
+/// Drain `out_fd` and `err_fd` into their buffers until both reach EOF, blocking on the `event:`
+/// scheme between iterations instead of favoring one fd or busy-spinning.
+fn read2(out_fd: usize, err_fd: usize, out_buf: &mut Vec<u8>, err_buf: &mut Vec<u8>) -> Result<()> {
+    try!(set_nonblocking(out_fd));
+    try!(set_nonblocking(err_fd));
+
+    let event_fd = try!(open("event:", 0).map_err(|x| Error::from_sys(x)));
+    try!(register_event(event_fd, out_fd));
+    try!(register_event(event_fd, err_fd));
+
+    let mut out_open = true;
+    let mut err_open = true;
+    let mut buf = [0; 4096];
+
+    while out_open || err_open {
+        if out_open {
+            match drain_fd(out_fd, &mut buf, out_buf) {
+                Ok(true) => out_open = false,
+                Ok(false) => (),
+                Err(err) => { let _ = close(event_fd); return Err(err); }
+            }
+        }
+
+        if err_open {
+            match drain_fd(err_fd, &mut buf, err_buf) {
+                Ok(true) => err_open = false,
+                Ok(false) => (),
+                Err(err) => { let _ = close(event_fd); return Err(err); }
+            }
+        }
+
+        if out_open || err_open {
+            let mut event = Event::default();
+            let event_buf = unsafe {
+                slice::from_raw_parts_mut(&mut event as *mut Event as *mut u8, mem::size_of::<Event>())
+            };
+            if let Err(err) = read(event_fd, event_buf).map_err(|x| Error::from_sys(x)) {
+                let _ = close(event_fd);
+                return Err(err);
+            }
+        }
+    }
+
+    let _ = close(event_fd);
+    Ok(())
+}
+
+/// Read whatever is available on `fd` into `out`; `Ok(true)` once EOF is reached.
+fn drain_fd(fd: usize, buf: &mut [u8], out: &mut Vec<u8>) -> Result<bool> {
+    loop {
+        match read(fd, buf) {
+            Ok(0) => return Ok(true),
+            Ok(n) => out.extend_from_slice(&buf[..n]),
+            Err(ref err) if err.errno == syscall::EAGAIN => return Ok(false),
+            Err(err) => return Err(Error::from_sys(err)),
+        }
     }
 }
 
+fn set_nonblocking(fd: usize) -> Result<()> {
+    let flags = try!(fcntl(fd, F_GETFL, 0).map_err(|x| Error::from_sys(x)));
+    try!(fcntl(fd, F_SETFL, flags | O_NONBLOCK).map_err(|x| Error::from_sys(x)));
+    Ok(())
+}
+
+fn register_event(event_fd: usize, fd: usize) -> Result<()> {
+    let event = Event {
+        id: fd,
+        flags: EVENT_READ,
+        data: 0,
+    };
+    let event_buf = unsafe {
+        slice::from_raw_parts(&event as *const Event as *const u8, mem::size_of::<Event>())
+    };
+    try!(write(event_fd, event_buf).map_err(|x| Error::from_sys(x)));
+    Ok(())
+}
+
+#[repr(C)]
+struct Winsize {
+    ws_row: u16,
+    ws_col: u16,
+    ws_xpixel: u16,
+    ws_ypixel: u16,
+}
+
+fn ioctl_resize(fd: usize, cols: u16, rows: u16) -> Result<()> {
+    let winsize = Winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    try!(ioctl(fd, TIOCSWINSZ, &winsize as *const Winsize as usize).map_err(|x| Error::from_sys(x)));
+    Ok(())
+}
+
 pub struct Command {
     path: String,
     args: Vec<String>,
-    env: BTreeMap<String, String>,
+    env: BTreeMap<String, Option<String>>,
+    env_clear: bool,
+    current_dir: Option<String>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    before_exec: Vec<Box<FnMut() -> Result<()> + Send>>,
+    pty: bool,
     stdin: Stdio,
     stdout: Stdio,
     stderr: Stdio,
@@ -117,7 +380,10 @@ impl fmt::Debug for Command {
             try!(write!(f, " {:?}", arg));
         }
         for (key, val) in &self.env {
-            try!(write!(f, " {:?}={:?}", key, val));
+            match *val {
+                Some(ref v) => { try!(write!(f, " {:?}={:?}", key, v)); },
+                None => { try!(write!(f, " -{:?}", key)); },
+            }
         }
         Ok(())
     }
@@ -129,6 +395,12 @@ impl Command {
             path: path.to_owned(),
             args: Vec::new(),
             env: BTreeMap::new(),
+            env_clear: false,
+            current_dir: None,
+            uid: None,
+            gid: None,
+            before_exec: Vec::new(),
+            pty: false,
             stdin: Stdio::inherit(),
             stdout: Stdio::inherit(),
             stderr: Stdio::inherit(),
@@ -140,8 +412,48 @@ impl Command {
         self
     }
 
+    /// Add multiple arguments at once, in order.
+    pub fn args<I, S>(&mut self, args: I) -> &mut Command
+        where I: IntoIterator<Item = S>, S: AsRef<str>
+    {
+        for arg in args {
+            self.args.push(arg.as_ref().to_owned());
+        }
+        self
+    }
+
     pub fn env(&mut self, key: &str, val: &str) -> &mut Command {
-        self.env.insert(key.to_owned(), val.to_owned());
+        self.env.insert(key.to_owned(), Some(val.to_owned()));
+        self
+    }
+
+    /// Insert multiple environment variables at once.
+    pub fn envs<I, K, V>(&mut self, vars: I) -> &mut Command
+        where I: IntoIterator<Item = (K, V)>, K: AsRef<str>, V: AsRef<str>
+    {
+        for (key, val) in vars {
+            self.env.insert(key.as_ref().to_owned(), Some(val.as_ref().to_owned()));
+        }
+        self
+    }
+
+    /// Remove an environment variable the child would otherwise inherit.
+    pub fn env_remove(&mut self, key: &str) -> &mut Command {
+        self.env.insert(key.to_owned(), None);
+        self
+    }
+
+    /// Clear the inherited environment; only vars set afterwards with `env`/`envs` are visible.
+    pub fn env_clear(&mut self) -> &mut Command {
+        self.env.clear();
+        self.env_clear = true;
+        self
+    }
+
+    /// Set the working directory the child should `chdir` into before `execve`. If the program
+    /// path is relative, which directory it resolves against is unspecified; prefer absolute.
+    pub fn current_dir(&mut self, dir: &str) -> &mut Command {
+        self.current_dir = Some(dir.to_owned());
         self
     }
 
@@ -160,10 +472,28 @@ impl Command {
         self
     }
 
+    /// Give the child a pty instead of `stdin`/`stdout`/`stderr`; `spawn` hands back the master
+    /// end as `Child::pty` and leaves `Child::stdin`/`stdout`/`stderr` unset.
+    pub fn pty(&mut self) -> &mut Command {
+        self.pty = true;
+        self
+    }
+
     pub fn spawn(&mut self) -> Result<Child> {
         self.exec(0)
     }
 
+    /// Run the command to completion, forcing `stdout`/`stderr` to `Stdio::piped()` and
+    /// collecting both.
+    pub fn output(&mut self) -> Result<Output> {
+        close_stdio(self.stdout.inner);
+        self.stdout(Stdio::piped());
+        close_stdio(self.stderr.inner);
+        self.stderr(Stdio::piped());
+        let child = try!(self.spawn());
+        child.wait_with_output()
+    }
+
     /// Spawn this command as a supervised process.
     ///
     /// This means that the system calls will block the process, until being handled by the
@@ -174,6 +504,8 @@ impl Command {
     }
 
     fn exec(&mut self, flags: usize) -> Result<Child> {
+        reap_orphans();
+
         let mut res = Box::new(0);
 
         let path = if self.path.contains(':') || self.path.contains('/') {
@@ -196,81 +528,147 @@ impl Command {
         }
 
         let env = self.env.clone();
+        let env_clear = self.env_clear;
+        let current_dir = self.current_dir.clone();
+
+        let child_pty = if self.pty {
+            Some(try!(openpty().map_err(|x| Error::from_sys(x))))
+        } else {
+            None
+        };
 
         let child_res = res.deref_mut() as *mut usize;
         let child_stderr = self.stderr.inner;
         let child_stdout = self.stdout.inner;
         let child_stdin = self.stdin.inner;
+        let child_uid = self.uid;
+        let child_gid = self.gid;
+        let mut child_before_exec = mem::replace(&mut self.before_exec, Vec::new());
         let child_code = Box::new(move || -> Result<usize> {
-            let child_stderr_res = match child_stderr {
-                StdioType::Piped(read, write) => {
-                    let _ = close(read);
-                    let _ = close(2);
-                    let dup_res = dup(write).map_err(|x| Error::from_sys(x));
-                    let _ = close(write);
-                    dup_res
-                },
-                StdioType::Raw(fd) => {
-                    let _ = close(2);
-                    let dup_res = dup(fd).map_err(|x| Error::from_sys(x));
-                    let _ = close(fd);
-                    dup_res
-                },
-                StdioType::Null => {
-                    let _ = close(2);
-                    Ok(0)
-                },
-                _ => Ok(0)
-            };
-
-            let child_stdout_res = match child_stdout {
-                StdioType::Piped(read, write) => {
-                    let _ = close(read);
-                    let _ = close(1);
-                    let dup_res = dup(write).map_err(|x| Error::from_sys(x));
-                    let _ = close(write);
-                    dup_res
-                },
-                StdioType::Raw(fd) => {
-                    let _ = close(1);
-                    let dup_res = dup(fd).map_err(|x| Error::from_sys(x));
-                    let _ = close(fd);
-                    dup_res
-                },
-                StdioType::Null => {
-                    let _ = close(1);
-                    Ok(0)
-                },
-                _ => Ok(0)
-            };
+            if let Some((master, slave)) = child_pty {
+                let _ = close(master);
+
+                // Don't leak unused stdin/stdout/stderr pipes into the child across execve.
+                close_stdio(child_stderr);
+                close_stdio(child_stdout);
+                close_stdio(child_stdin);
+
+                let _ = close(0);
+                try!(dup(slave).map_err(|x| Error::from_sys(x)));
+                let _ = close(1);
+                try!(dup(slave).map_err(|x| Error::from_sys(x)));
+                let _ = close(2);
+                try!(dup(slave).map_err(|x| Error::from_sys(x)));
+                let _ = close(slave);
+
+                try!(setsid().map_err(|x| Error::from_sys(x)));
+                try!(ioctl(0, TIOCSCTTY, 0).map_err(|x| Error::from_sys(x)));
+            } else {
+                let child_stderr_res = match child_stderr {
+                    StdioType::Piped(read, write) => {
+                        let _ = close(read);
+                        let _ = close(2);
+                        let dup_res = dup(write).map_err(|x| Error::from_sys(x));
+                        let _ = close(write);
+                        dup_res
+                    },
+                    StdioType::Raw(fd) => {
+                        let _ = close(2);
+                        let dup_res = dup(fd).map_err(|x| Error::from_sys(x));
+                        let _ = close(fd);
+                        dup_res
+                    },
+                    StdioType::Null => {
+                        let _ = close(2);
+                        Ok(0)
+                    },
+                    _ => Ok(0)
+                };
+
+                let child_stdout_res = match child_stdout {
+                    StdioType::Piped(read, write) => {
+                        let _ = close(read);
+                        let _ = close(1);
+                        let dup_res = dup(write).map_err(|x| Error::from_sys(x));
+                        let _ = close(write);
+                        dup_res
+                    },
+                    StdioType::Raw(fd) => {
+                        let _ = close(1);
+                        let dup_res = dup(fd).map_err(|x| Error::from_sys(x));
+                        let _ = close(fd);
+                        dup_res
+                    },
+                    StdioType::Null => {
+                        let _ = close(1);
+                        Ok(0)
+                    },
+                    _ => Ok(0)
+                };
+
+                let child_stdin_res = match child_stdin {
+                    StdioType::Piped(read, write) => {
+                        let _ = close(write);
+                        let _ = close(0);
+                        let dup_res = dup(read).map_err(|x| Error::from_sys(x));
+                        let _ = close(read);
+                        dup_res
+                    },
+                    StdioType::Raw(fd) => {
+                        let _ = close(0);
+                        let dup_res = dup(fd).map_err(|x| Error::from_sys(x));
+                        let _ = close(fd);
+                        dup_res
+                    },
+                    StdioType::Null => {
+                        let _ = close(0);
+                        Ok(0)
+                    },
+                    _ => Ok(0)
+                };
+
+                let _ = try!(child_stderr_res);
+                let _ = try!(child_stdout_res);
+                let _ = try!(child_stdin_res);
+            }
 
-            let child_stdin_res = match child_stdin {
-                StdioType::Piped(read, write) => {
-                    let _ = close(write);
-                    let _ = close(0);
-                    let dup_res = dup(read).map_err(|x| Error::from_sys(x));
-                    let _ = close(read);
-                    dup_res
-                },
-                StdioType::Raw(fd) => {
-                    let _ = close(0);
-                    let dup_res = dup(fd).map_err(|x| Error::from_sys(x));
-                    let _ = close(fd);
-                    dup_res
-                },
-                StdioType::Null => {
-                    let _ = close(0);
-                    Ok(0)
-                },
-                _ => Ok(0)
-            };
+            if let Some(ref dir) = current_dir {
+                try!(chdir(dir).map_err(|x| Error::from_sys(x)));
+            }
 
-            let _ = try!(child_stderr_res);
-            let _ = try!(child_stdout_res);
-            let _ = try!(child_stdin_res);
+            if env_clear {
+                let keys: Vec<String> = env::vars().map(|(key, _)| key).collect();
+                for key in keys.iter() {
+                    env::remove_var(key);
+                }
+            }
 
             for (key, val) in env.iter() {
-                env::set_var(key, val);
+                match *val {
+                    Some(ref v) => env::set_var(key, v),
+                    None => env::remove_var(key),
+                }
+            }
+
+            if child_uid.is_some() || child_gid.is_some() {
+                // Only root can actually drop supplementary groups; a non-root
+                // caller using uid()/gid() for an otherwise-successful no-op
+                // shouldn't have the whole spawn fail with EPERM here.
+                if try!(getuid().map_err(|x| Error::from_sys(x))) == 0 {
+                    try!(setgroups(0, 0).map_err(|x| Error::from_sys(x)));
+                }
+            }
+
+            if let Some(gid) = child_gid {
+                try!(setgid(gid as usize).map_err(|x| Error::from_sys(x)));
+            }
+
+            if let Some(uid) = child_uid {
+                try!(setuid(uid as usize).map_err(|x| Error::from_sys(x)));
+            }
+
+            for hook in child_before_exec.iter_mut() {
+                try!(hook());
             }
 
             execve(&path, &args).map_err(|x| Error::from_sys(x))
@@ -290,6 +688,11 @@ impl Command {
                 // Must forget child_code to prevent double free
                 mem::forget(child_code);
                 if let Err(err) = SysError::demux(*res) {
+                    if let Some((master, slave)) = child_pty {
+                        let _ = close(master);
+                        let _ = close(slave);
+                    }
+
                     match self.stdin.inner {
                         StdioType::Piped(read, write) => {
                             let _ = close(read);
@@ -325,48 +728,68 @@ impl Command {
 
                     Err(Error::from_sys(err))
                 } else {
-                    Ok(Child {
-                        pid: pid,
-                        stdin: match self.stdin.inner {
-                            StdioType::Piped(read, write) => {
-                                let _ = close(read);
-                                Some(ChildStdin {
-                                    fd: write
-                                })
-                            },
-                            StdioType::Raw(fd) => {
-                                let _ = close(fd);
-                                None
+                    if let Some((master, slave)) = child_pty {
+                        let _ = close(slave);
+
+                        // The pty took over stdin/stdout/stderr; drop the unused pipes here too.
+                        close_stdio(self.stdin.inner);
+                        close_stdio(self.stdout.inner);
+                        close_stdio(self.stderr.inner);
+
+                        Ok(Child {
+                            pid: pid,
+                            exit_status: None,
+                            pty: Some(ChildPty { fd: master }),
+                            stdin: None,
+                            stdout: None,
+                            stderr: None,
+                        })
+                    } else {
+                        Ok(Child {
+                            pid: pid,
+                            exit_status: None,
+                            pty: None,
+                            stdin: match self.stdin.inner {
+                                StdioType::Piped(read, write) => {
+                                    let _ = close(read);
+                                    Some(ChildStdin {
+                                        fd: write
+                                    })
+                                },
+                                StdioType::Raw(fd) => {
+                                    let _ = close(fd);
+                                    None
+                                },
+                                _ => None
                             },
-                            _ => None
-                        },
-                        stdout: match self.stdout.inner {
-                            StdioType::Piped(read, write) => {
-                                let _ = close(write);
-                                Some(ChildStdout {
-                                    fd: read
-                                })
+                            stdout: match self.stdout.inner {
+                                StdioType::Piped(read, write) => {
+                                    let _ = close(write);
+                                    Some(ChildStdout {
+                                        fd: read
+                                    })
+                                },
+                                StdioType::Raw(fd) => {
+                                    let _ = close(fd);
+                                    None
+                                },
+                                _ => None
                             },
-                            StdioType::Raw(fd) => {
-                                let _ = close(fd);
-                                None
-                            },
-                            _ => None
-                        },
-                        stderr: match self.stderr.inner {
-                            StdioType::Piped(read, write) => {
-                                let _ = close(write);
-                                Some(ChildStderr {
-                                    fd: read
-                                })
-                            },
-                            StdioType::Raw(fd) => {
-                                let _ = close(fd);
-                                None
-                            },
-                            _ => None
-                        }
-                    })
+                            stderr: match self.stderr.inner {
+                                StdioType::Piped(read, write) => {
+                                    let _ = close(write);
+                                    Some(ChildStderr {
+                                        fd: read
+                                    })
+                                },
+                                StdioType::Raw(fd) => {
+                                    let _ = close(fd);
+                                    None
+                                },
+                                _ => None
+                            }
+                        })
+                    }
                 }
             }
             Err(err) => Err(Error::from_sys(err))
@@ -374,6 +797,39 @@ impl Command {
     }
 }
 
+/// Unix-specific extensions to `Command`, mirroring `std::os::unix::process::CommandExt`.
+pub trait CommandExt {
+    /// Set the user id of the child process.
+    fn uid(&mut self, id: u32) -> &mut Command;
+
+    /// Set the group id of the child process.
+    fn gid(&mut self, id: u32) -> &mut Command;
+
+    /// Schedule a closure to run in the child between `clone` and `execve`. Unlike `uid`/`gid`,
+    /// hooks are consumed by `spawn`/`output`, so add them again before reusing the `Command`.
+    fn before_exec<F>(&mut self, f: F) -> &mut Command
+        where F: FnMut() -> Result<()> + Send + 'static;
+}
+
+impl CommandExt for Command {
+    fn uid(&mut self, id: u32) -> &mut Command {
+        self.uid = Some(id);
+        self
+    }
+
+    fn gid(&mut self, id: u32) -> &mut Command {
+        self.gid = Some(id);
+        self
+    }
+
+    fn before_exec<F>(&mut self, f: F) -> &mut Command
+        where F: FnMut() -> Result<()> + Send + 'static
+    {
+        self.before_exec.push(Box::new(f));
+        self
+    }
+}
+
 #[derive(Copy, Clone)]
 enum StdioType {
     Piped(usize, usize),
@@ -382,6 +838,19 @@ enum StdioType {
     Null,
 }
 
+fn close_stdio(cfg: StdioType) {
+    match cfg {
+        StdioType::Piped(read, write) => {
+            let _ = close(read);
+            let _ = close(write);
+        },
+        StdioType::Raw(fd) => {
+            let _ = close(fd);
+        },
+        _ => ()
+    }
+}
+
 pub struct Stdio {
     inner: StdioType,
 }